@@ -0,0 +1,76 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+/// Every way `cargo remote` can fail, one variant per pipeline stage. Each
+/// variant maps to a fixed exit code (see [`Error::exit_code`]), except
+/// [`Error::BuildFailed`], which passes the remote build's own status through,
+/// instead of the scattered magic numbers `main` used to `exit()` with directly.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Exit code 1: `cargo metadata` could not be resolved for the local project.
+    #[error("failed to resolve cargo metadata for '{manifest_path}': {source}")]
+    Metadata {
+        manifest_path: PathBuf,
+        #[source]
+        source: cargo_metadata::Error,
+    },
+
+    /// Exit code 2: no remote build server was configured.
+    #[error("no remote build server was defined (use a config file or --remote)")]
+    NoRemoteServer,
+
+    /// Exit code 3: transferring the project sources to the build server failed.
+    #[error("failed to transfer project to build server '{remote}': {source}")]
+    SourceTransfer {
+        remote: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// Exit code 4: the remote `cargo` invocation itself could not be started or
+    /// observed over ssh (as opposed to the remote command running and failing).
+    #[error("failed to run cargo command remotely on '{remote}': {source}")]
+    RemoteBuild {
+        remote: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// Exit code: the remote `cargo` invocation's own exit status, passed through
+    /// unchanged so a failing remote build looks like a failing local one.
+    #[error("remote build exited with status {0}")]
+    BuildFailed(i32),
+
+    /// Exit code 6: transferring build artifacts or `Cargo.lock` back to the
+    /// local machine failed.
+    #[error("failed to transfer {what} back from '{remote}': {source}")]
+    CopyBack {
+        what: &'static str,
+        remote: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// Exit code 7: transferring patched workspaces or external path
+    /// dependencies to the build server failed. The remote project tree may
+    /// now be missing sources or contain stale ones, so this aborts the
+    /// build rather than proceeding against it.
+    #[error("failed to transfer patched dependencies to the build server: {0}")]
+    PatchTransfer(String),
+}
+
+impl Error {
+    /// The process exit code this error should be reported with.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Metadata { .. } => 1,
+            Error::NoRemoteServer => 2,
+            Error::SourceTransfer { .. } => 3,
+            Error::RemoteBuild { .. } => 4,
+            Error::BuildFailed(status) => *status,
+            Error::CopyBack { .. } => 6,
+            Error::PatchTransfer(_) => 7,
+        }
+    }
+}
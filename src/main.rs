@@ -1,15 +1,62 @@
 use std::ffi::OsStr;
-use std::path::PathBuf;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::process::{exit, Command, Stdio};
 use structopt::StructOpt;
 use toml::Value;
 
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
 
 const PROGRESS_FLAG: &str = "--info=progress2";
 
+mod error;
 mod patches;
 
+use error::Error;
+
+/// Formats a [`Command`] as a single copy-pasteable shell line.
+pub(crate) fn format_command(command: &Command) -> String {
+    let quote = |arg: &OsStr| {
+        let arg = arg.to_string_lossy();
+        let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+        if !arg.is_empty() && arg.chars().all(plain) {
+            arg.into_owned()
+        } else {
+            format!("'{}'", arg.replace('\'', r"'\''"))
+        }
+    };
+    let program = quote(command.get_program());
+    command
+        .get_args()
+        .fold(program, |line, arg| line + " " + &quote(arg))
+}
+
+/// Runs ssh/rsync invocations through here so each one is logged in a
+/// single, consistent, copy-pasteable form (the piped ssh write of patched
+/// manifests in `patches.rs` logs itself in the same form). In `--dry-run`, the command is
+/// logged and not actually spawned; callers get a synthetic success result.
+pub(crate) fn run_command(
+    command: &mut Command,
+    dry_run: bool,
+) -> Result<std::process::Output, std::io::Error> {
+    info!("$ {}", format_command(command));
+
+    if dry_run {
+        #[cfg(unix)]
+        let status = std::os::unix::process::ExitStatusExt::from_raw(0);
+        #[cfg(windows)]
+        let status = std::os::windows::process::ExitStatusExt::from_raw(0);
+
+        return Ok(std::process::Output {
+            status,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        });
+    }
+
+    command.output()
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "cargo-remote", bin_name = "cargo")]
 enum Opts {
@@ -18,29 +65,33 @@ enum Opts {
         #[structopt(short = "r", long = "remote", help = "Remote ssh build server")]
         remote: Option<String>,
 
+        #[structopt(
+            short = "p",
+            long = "profile",
+            help = "Named profile from .cargo-remote.toml to build with ([profile.<name>] section)"
+        )]
+        profile: Option<String>,
+
         #[structopt(
             short = "b",
             long = "build-env",
-            help = "Set remote environment variables. RUST_BACKTRACE, CC, LIB, etc. ",
-            default_value = "RUST_BACKTRACE=1"
+            help = "Set remote environment variables. RUST_BACKTRACE, CC, LIB, etc. Defaults to RUST_BACKTRACE=1"
         )]
-        build_env: String,
+        build_env: Option<String>,
 
         #[structopt(
             short = "d",
             long = "rustup-default",
-            help = "Rustup default (stable|beta|nightly)",
-            default_value = "stable"
+            help = "Rustup default (stable|beta|nightly). Defaults to stable"
         )]
-        rustup_default: String,
+        rustup_default: Option<String>,
 
         #[structopt(
             short = "e",
             long = "env",
-            help = "Environment profile. default_value = /etc/profile",
-            default_value = "/etc/profile"
+            help = "Environment profile. Defaults to /etc/profile"
         )]
-        env: String,
+        env: Option<String>,
 
         #[structopt(
             short = "c",
@@ -70,6 +121,12 @@ enum Opts {
         )]
         hidden: bool,
 
+        #[structopt(
+            long = "git-only",
+            help = "Only transfer files tracked by git (honours .gitignore) instead of the whole tree; falls back to the normal transfer if the project isn't a git repository"
+        )]
+        git_only: bool,
+
         #[structopt(help = "cargo command that will be executed remotely")]
         command: String,
 
@@ -81,6 +138,24 @@ enum Opts {
 
         #[structopt(help = "ignore patches", long = "ignore-patches")]
         ignore_patches: bool,
+
+        #[structopt(
+            long = "dry-run",
+            help = "Print every ssh/rsync command that would be run, in order, and exit without running any of them"
+        )]
+        dry_run: bool,
+
+        #[structopt(
+            long = "target",
+            help = "Install an additional rustup target on the build server before building (can be given multiple times)"
+        )]
+        target: Vec<String>,
+
+        #[structopt(
+            long = "component",
+            help = "Install an additional rustup component on the build server before building, e.g. clippy, rustfmt, llvm-tools-preview (can be given multiple times)"
+        )]
+        component: Vec<String>,
     },
 }
 
@@ -111,6 +186,102 @@ fn config_from_file(config_path: &PathBuf) -> Option<Value> {
     Some(value)
 }
 
+/// Remote-build settings that can come from a `[default]` or `[profile.<name>]`
+/// section of `.cargo-remote.toml`. Every field is optional: a config file only
+/// needs to set what it wants to override, and `--profile <name>` picks which
+/// `[profile.<name>]` section is layered on top of `[default]`.
+#[derive(Debug, Default, Clone)]
+struct ProfileConfig {
+    remote: Option<String>,
+    build_env: Option<String>,
+    rustup_default: Option<String>,
+    env: Option<String>,
+    targets: Vec<String>,
+    copy_back: Option<String>,
+}
+
+impl ProfileConfig {
+    /// Reads the fields we know about out of a toml table, leaving anything
+    /// absent or mistyped as `None`/empty.
+    fn from_table(table: &Value) -> Self {
+        ProfileConfig {
+            remote: table
+                .get("remote")
+                .and_then(Value::as_str)
+                .map(String::from),
+            build_env: table
+                .get("build_env")
+                .and_then(Value::as_str)
+                .map(String::from),
+            rustup_default: table
+                .get("rustup_default")
+                .and_then(Value::as_str)
+                .map(String::from),
+            env: table.get("env").and_then(Value::as_str).map(String::from),
+            targets: table
+                .get("targets")
+                .and_then(Value::as_array)
+                .map(|targets| {
+                    targets
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            copy_back: table
+                .get("copy_back")
+                .and_then(Value::as_str)
+                .map(String::from),
+        }
+    }
+
+    /// Overlays `more_specific` on top of `self`, letting any field it sets win.
+    /// Used to layer `[profile.<name>]` over `[default]`, and a project-local
+    /// config file over the user's global one.
+    fn overlaid_with(mut self, more_specific: ProfileConfig) -> Self {
+        if more_specific.remote.is_some() {
+            self.remote = more_specific.remote;
+        }
+        if more_specific.build_env.is_some() {
+            self.build_env = more_specific.build_env;
+        }
+        if more_specific.rustup_default.is_some() {
+            self.rustup_default = more_specific.rustup_default;
+        }
+        if more_specific.env.is_some() {
+            self.env = more_specific.env;
+        }
+        if !more_specific.targets.is_empty() {
+            self.targets = more_specific.targets;
+        }
+        if more_specific.copy_back.is_some() {
+            self.copy_back = more_specific.copy_back;
+        }
+        self
+    }
+}
+
+/// Resolves the `[default]` + `[profile.<name>]` layering for a single config
+/// file: un-nested top-level keys (the pre-`[default]` file format) form the
+/// least-specific base layer, `[default]` overrides those, and
+/// `[profile.<name>]` selectively overrides both.
+fn complete_from_config(config: &Value, profile: Option<&str>) -> ProfileConfig {
+    let legacy_top_level = ProfileConfig::from_table(config);
+
+    let default = config
+        .get("default")
+        .map(ProfileConfig::from_table)
+        .unwrap_or_default();
+
+    let profile = profile
+        .and_then(|name| config.get("profile").and_then(|p| p.get(name)))
+        .map(ProfileConfig::from_table)
+        .unwrap_or_default();
+
+    legacy_top_level.overlaid_with(default).overlaid_with(profile)
+}
+
 fn main() {
     simple_logger::SimpleLogger::new()
         .with_level(log::LevelFilter::Error)
@@ -118,8 +289,21 @@ fn main() {
         .init()
         .unwrap();
 
+    if let Err(err) = run() {
+        error!("{}", err);
+        let mut source = std::error::Error::source(&err);
+        while let Some(err) = source {
+            error!("caused by: {}", err);
+            source = err.source();
+        }
+        exit(err.exit_code());
+    }
+}
+
+fn run() -> Result<(), Error> {
     let Opts::Remote {
         remote,
+        profile,
         build_env,
         rustup_default,
         env,
@@ -127,25 +311,22 @@ fn main() {
         no_copy_lock,
         manifest_path,
         hidden,
+        git_only,
         command,
         options,
         ignore_patches,
+        dry_run,
+        mut target,
+        component,
     } = Opts::from_args();
 
     let mut metadata_cmd = cargo_metadata::MetadataCommand::new();
-    metadata_cmd.manifest_path(manifest_path).no_deps();
+    metadata_cmd.manifest_path(manifest_path.clone()).no_deps();
 
-    let project_metadata = match metadata_cmd.exec() {
-        Ok(m) => m,
-        Err(cargo_metadata::Error::CargoMetadata { stderr }) => {
-            error!("Cargo Metadata execution failed:\n{}", stderr);
-            exit(1)
-        }
-        Err(e) => {
-            error!("Cargo Metadata failed:\n{:?}", e);
-            exit(1)
-        }
-    };
+    let project_metadata = metadata_cmd.exec().map_err(|source| Error::Metadata {
+        manifest_path: manifest_path.clone(),
+        source,
+    })?;
     let project_dir = project_metadata.workspace_root.clone().into_std_path_buf();
     debug!("Project dir: {:?}", project_dir);
 
@@ -169,26 +350,36 @@ fn main() {
     let build_path = format!("{}/{}/", build_path_folder, project_name.to_string_lossy());
 
     debug!("Project name: {:?}", project_name);
-    let configs = vec![
-        config_from_file(&project_dir.join(".cargo-remote.toml")),
+    // Global config is the least specific; the project-local config is more
+    // specific and overrides it where both set the same field.
+    let config = vec![
         xdg::BaseDirectories::with_prefix("cargo-remote")
             .ok()
             .and_then(|base| base.find_config_file("cargo-remote.toml"))
             .and_then(|p| config_from_file(&p)),
-    ];
-
-    // TODO: move Opts::Remote fields into own type and implement complete_from_config(&mut self, config: &Value)
-    let build_server = remote
-        .or_else(|| {
-            configs
-                .into_iter()
-                .flat_map(|config| config.and_then(|c| c["remote"].as_str().map(String::from)))
-                .next()
-        })
-        .unwrap_or_else(|| {
-            error!("No remote build server was defined (use config file or --remote flag)");
-            exit(-3);
-        });
+        config_from_file(&project_dir.join(".cargo-remote.toml")),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|config| complete_from_config(&config, profile.as_deref()))
+    .fold(ProfileConfig::default(), ProfileConfig::overlaid_with);
+
+    if target.is_empty() {
+        target = config.targets.clone();
+    }
+
+    let build_env = build_env
+        .or_else(|| config.build_env.clone())
+        .unwrap_or_else(|| "RUST_BACKTRACE=1".to_string());
+    let rustup_default = rustup_default
+        .or_else(|| config.rustup_default.clone())
+        .unwrap_or_else(|| "stable".to_string());
+    let env = env
+        .or_else(|| config.env.clone())
+        .unwrap_or_else(|| "/etc/profile".to_string());
+    let copy_back = copy_back.or_else(|| config.copy_back.clone().map(Some));
+
+    let build_server = remote.or_else(|| config.remote.clone()).ok_or(Error::NoRemoteServer)?;
 
     debug!("Transferring sources to build server.");
     // transfer project to build server
@@ -196,29 +387,46 @@ fn main() {
         &format!("{}/", project_dir.display()),
         &format!("{}:{}", build_server, build_path),
         hidden,
+        git_only,
+        dry_run,
     )
-    .unwrap_or_else(|e| {
-        error!("Failed to transfer project to build server (error: {})", e);
-        exit(-4);
-    });
+    .map_err(|source| Error::SourceTransfer {
+        remote: build_server.clone(),
+        source,
+    })?;
 
     if !ignore_patches {
-        patches::handle_patches(&build_path, &build_server, manifest_path, hidden).unwrap_or_else(
-            |err| {
-                log::error!("Could not transfer patched workspaces to remote: {}", err);
-            },
-        );
+        patches::handle_patches(
+            &build_path,
+            &build_server,
+            manifest_path,
+            hidden,
+            git_only,
+            dry_run,
+        )?;
     } else {
         log::debug!("Potential patches will be ignored due to command line flag.");
     }
 
+    // rustup already no-ops when a target/component is installed, so these can be
+    // run unconditionally every time without slowing down the common case.
+    let mut toolchain_setup = String::new();
+    if !target.is_empty() {
+        toolchain_setup.push_str(&format!("rustup target add {}; ", target.join(" ")));
+    }
+    if !component.is_empty() {
+        toolchain_setup.push_str(&format!("rustup component add {}; ", component.join(" ")));
+    }
+
     debug!("Build ENV: {:?}", build_env);
     debug!("Environment profile: {:?}", env);
     debug!("Build path: {:?}", build_path);
+    debug!("Toolchain setup: {:?}", toolchain_setup);
     let build_command = format!(
-        "source {}; rustup default {}; cd {}; {} cargo {} {}",
+        "source {}; rustup default {}; {}cd {}; {} cargo {} {}",
         env,
         rustup_default,
+        toolchain_setup,
         build_path,
         build_env,
         command,
@@ -226,86 +434,143 @@ fn main() {
     );
 
     debug!("Starting build process.");
-    let output = Command::new("ssh")
-        .arg("-t")
-        .arg(&build_server)
-        .arg(build_command)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .stdin(Stdio::inherit())
-        .output()
-        .unwrap_or_else(|e| {
-            error!("Failed to run cargo command remotely (error: {})", e);
-            exit(-5);
-        });
+    let output = run_command(
+        Command::new("ssh")
+            .arg("-t")
+            .arg(&build_server)
+            .arg(build_command)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .stdin(Stdio::inherit()),
+        dry_run,
+    )
+    .map_err(|source| Error::RemoteBuild {
+        remote: build_server.clone(),
+        source,
+    })?;
 
     if let Some(file_name) = copy_back {
         debug!("Transferring artifacts back to client.");
         let file_name = file_name.unwrap_or_else(String::new);
-        Command::new("rsync")
-            .arg(if std::env::consts::OS == "macos" {
-                "-vrltogD"
-            } else {
-                "-a"
-            })
-            .arg("-q")
-            .arg("--delete")
-            .arg("--compress")
-            .arg(PROGRESS_FLAG)
-            .arg(format!(
-                "{}:{}target/{}",
-                build_server, build_path, file_name
-            ))
-            .arg(format!("{}/target/{}", project_dir.display(), file_name))
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .stdin(Stdio::inherit())
-            .output()
-            .unwrap_or_else(|e| {
-                error!(
-                    "Failed to transfer target back to local machine (error: {})",
-                    e
-                );
-                exit(-6);
-            });
+        run_command(
+            Command::new("rsync")
+                .arg(if std::env::consts::OS == "macos" {
+                    "-vrltogD"
+                } else {
+                    "-a"
+                })
+                .arg("-q")
+                .arg("--delete")
+                .arg("--compress")
+                .arg(PROGRESS_FLAG)
+                .arg(format!(
+                    "{}:{}target/{}",
+                    build_server, build_path, file_name
+                ))
+                .arg(format!("{}/target/{}", project_dir.display(), file_name))
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .stdin(Stdio::inherit()),
+            dry_run,
+        )
+        .map_err(|source| Error::CopyBack {
+            what: "target directory",
+            remote: build_server.clone(),
+            source,
+        })?;
     }
 
     if !no_copy_lock {
         debug!("Transferring Cargo.lock file back to client.");
-        Command::new("rsync")
-            .arg(if std::env::consts::OS == "macos" {
-                "-vrltogD"
-            } else {
-                "-a"
-            })
-            .arg("-q")
-            .arg("--delete")
-            .arg("--compress")
-            .arg(PROGRESS_FLAG)
-            .arg(format!("{}:{}/Cargo.lock", build_server, build_path))
-            .arg(format!("{}/Cargo.lock", project_dir.display()))
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .stdin(Stdio::inherit())
-            .output()
-            .unwrap_or_else(|e| {
-                error!(
-                    "Failed to transfer Cargo.lock back to local machine (error: {})",
-                    e
-                );
-                exit(-7);
-            });
+        run_command(
+            Command::new("rsync")
+                .arg(if std::env::consts::OS == "macos" {
+                    "-vrltogD"
+                } else {
+                    "-a"
+                })
+                .arg("-q")
+                .arg("--delete")
+                .arg("--compress")
+                .arg(PROGRESS_FLAG)
+                .arg(format!("{}:{}/Cargo.lock", build_server, build_path))
+                .arg(format!("{}/Cargo.lock", project_dir.display()))
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .stdin(Stdio::inherit()),
+            dry_run,
+        )
+        .map_err(|source| Error::CopyBack {
+            what: "Cargo.lock",
+            remote: build_server.clone(),
+            source,
+        })?;
     }
 
     if !output.status.success() {
-        exit(output.status.code().unwrap_or(1))
+        return Err(Error::BuildFailed(output.status.code().unwrap_or(1)));
     }
+
+    Ok(())
+}
+
+/// Enumerates every file `git` would ship for `local_dir` (tracked files, plus
+/// untracked files that aren't excluded by `.gitignore`/`.git/info/exclude`)
+/// and writes the NUL-delimited list to a temp file for rsync's
+/// `--files-from`. Returns `None` (the caller should fall back to the default
+/// `--exclude`-based transfer) if `local_dir` isn't inside a git repository or
+/// the list can't be written.
+///
+/// Known limitation: combined with `--delete`, rsync only prunes stale
+/// remote files inside directories it actually visits, and with
+/// `--files-from` that's exactly the directories containing a listed file.
+/// If every file under some directory was deleted locally, that directory
+/// has no entries left in this list, rsync never visits it, and the
+/// now-orphaned directory is left behind on the remote. This is a
+/// documented rsync behavior, not a bug in `--files-from` handling here.
+fn git_file_list(local_dir: &Path) -> Option<PathBuf> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(local_dir)
+        .args(["ls-files", "--cached", "--others", "--exclude-standard", "-z"])
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        debug!(
+            "'{}' is not a git repository, falling back to the default file list",
+            local_dir.display()
+        );
+        return None;
+    }
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    local_dir.hash(&mut hasher);
+    let list_path = std::env::temp_dir().join(format!(
+        "cargo-remote-files-{}-{:x}.lst",
+        std::process::id(),
+        hasher.finish()
+    ));
+
+    std::fs::write(&list_path, output.stdout)
+        .map_err(|e| {
+            warn!(
+                "Could not write git file list to '{}' (error: {})",
+                list_path.display(),
+                e
+            );
+        })
+        .ok()?;
+
+    Some(list_path)
 }
 
 pub fn copy_to_remote(
     local_dir: &str,
     remote_dir: &str,
     hidden: bool,
+    git_only: bool,
+    dry_run: bool,
 ) -> Result<std::process::Output, std::io::Error> {
     let mut rsync_to = Command::new("rsync");
     rsync_to
@@ -317,12 +582,23 @@ pub fn copy_to_remote(
         .arg("-q")
         .arg("--delete")
         .arg("--compress")
-        .arg(PROGRESS_FLAG)
-        .arg("--exclude")
-        .arg("target");
+        .arg(PROGRESS_FLAG);
+
+    let file_list = if git_only {
+        git_file_list(Path::new(local_dir.trim_end_matches('/')))
+    } else {
+        None
+    };
 
-    if !hidden {
-        rsync_to.arg("--exclude").arg(".*");
+    if let Some(file_list_path) = &file_list {
+        rsync_to
+            .arg(format!("--files-from={}", file_list_path.display()))
+            .arg("--from0");
+    } else {
+        rsync_to.arg("--exclude").arg("target");
+        if !hidden {
+            rsync_to.arg("--exclude").arg(".*");
+        }
     }
 
     rsync_to
@@ -332,6 +608,104 @@ pub fn copy_to_remote(
         .arg(remote_dir)
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
-        .stdin(Stdio::inherit())
-        .output()
+        .stdin(Stdio::inherit());
+
+    let result = run_command(&mut rsync_to, dry_run);
+
+    // In a dry run the command was only printed, not executed, so the file
+    // list it references must survive for the user to copy-paste and run it.
+    if !dry_run {
+        if let Some(file_list_path) = file_list {
+            let _ = std::fs::remove_file(file_list_path);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{complete_from_config, ProfileConfig};
+    use toml::Value;
+
+    #[test]
+    fn from_table_reads_known_fields() {
+        let table: Value = toml::from_str(
+            r#"
+remote = "myserver"
+build_env = "RUST_BACKTRACE=1"
+rustup_default = "nightly"
+env = "/etc/profile.d/rust.sh"
+targets = ["wasm32-unknown-unknown", "x86_64-unknown-linux-musl"]
+copy_back = "target/release/app"
+"#,
+        )
+        .unwrap();
+
+        let config = ProfileConfig::from_table(&table);
+        assert_eq!(config.remote.as_deref(), Some("myserver"));
+        assert_eq!(config.build_env.as_deref(), Some("RUST_BACKTRACE=1"));
+        assert_eq!(config.rustup_default.as_deref(), Some("nightly"));
+        assert_eq!(config.env.as_deref(), Some("/etc/profile.d/rust.sh"));
+        assert_eq!(
+            config.targets,
+            vec!["wasm32-unknown-unknown", "x86_64-unknown-linux-musl"]
+        );
+        assert_eq!(config.copy_back.as_deref(), Some("target/release/app"));
+    }
+
+    #[test]
+    fn overlaid_with_lets_more_specific_fields_win() {
+        let base = ProfileConfig {
+            remote: Some("base-server".to_string()),
+            build_env: Some("base-env".to_string()),
+            ..ProfileConfig::default()
+        };
+        let more_specific = ProfileConfig {
+            remote: Some("override-server".to_string()),
+            ..ProfileConfig::default()
+        };
+
+        let result = base.overlaid_with(more_specific);
+        assert_eq!(result.remote.as_deref(), Some("override-server"));
+        // Fields the more specific layer left unset stay on the base layer.
+        assert_eq!(result.build_env.as_deref(), Some("base-env"));
+    }
+
+    #[test]
+    fn complete_from_config_still_reads_legacy_flat_files() {
+        // Before [default]/[profile.*] existed, a config file set these
+        // fields directly at the top level.
+        let config: Value = toml::from_str(r#"remote = "myserver""#).unwrap();
+
+        let resolved = complete_from_config(&config, None);
+        assert_eq!(resolved.remote.as_deref(), Some("myserver"));
+    }
+
+    #[test]
+    fn complete_from_config_layers_default_and_profile_over_legacy_flat_fields() {
+        let config: Value = toml::from_str(
+            r#"
+remote = "legacy-server"
+build_env = "legacy-env"
+
+[default]
+remote = "default-server"
+
+[profile.release]
+remote = "release-server"
+"#,
+        )
+        .unwrap();
+
+        // No profile selected: [default] overrides the legacy top-level
+        // value, but a field [default] doesn't set still falls back to it.
+        let resolved = complete_from_config(&config, None);
+        assert_eq!(resolved.remote.as_deref(), Some("default-server"));
+        assert_eq!(resolved.build_env.as_deref(), Some("legacy-env"));
+
+        // Selecting a profile overrides [default] in turn.
+        let resolved = complete_from_config(&config, Some("release"));
+        assert_eq!(resolved.remote.as_deref(), Some("release-server"));
+    }
 }
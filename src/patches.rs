@@ -1,25 +1,46 @@
 use crate::copy_to_remote;
+use cargo_metadata::DependencyKind;
 use std::ffi::OsString;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use toml_edit::{Document, InlineTable};
 
-/// Handle patched dependencies in a Cargo.toml file.
-/// Adjustments are only needed when patches point to local files.
+/// Handle patched dependencies and external path dependencies in a Cargo.toml file.
+/// Adjustments are only needed when they point to local files.
 /// Steps:
 /// 1. Read Cargo.toml of project
-/// 2. Extract list of patches
-/// 3. For each patched crate, check if there is a path given. If not, ignore.
-/// 4. Find the workspace of the patched crate via `cargo locate-project --workspace`
-/// 5. Add workspace to the list of projects that need to be copied
-/// 6. Copy folders via rsync
+/// 2. Extract list of patches, and separately the plain `path` dependencies
+///    that point outside the workspace
+/// 3. For each one, find its workspace root via `cargo metadata`
+/// 4. Add that workspace to the list of projects that need to be copied
+/// 5. Copy folders via rsync, skipping workspaces already queued
 pub fn handle_patches(
-    build_path: &String,
-    build_server: &String,
+    build_path: &str,
+    build_server: &str,
     manifest_path: PathBuf,
     copy_hidden_files: bool,
-    no_transfer_git: bool,
+    git_only: bool,
+    dry_run: bool,
+) -> Result<(), crate::error::Error> {
+    handle_patches_inner(
+        build_path,
+        build_server,
+        manifest_path,
+        copy_hidden_files,
+        git_only,
+        dry_run,
+    )
+    .map_err(crate::error::Error::PatchTransfer)
+}
+
+fn handle_patches_inner(
+    build_path: &str,
+    build_server: &str,
+    manifest_path: PathBuf,
+    copy_hidden_files: bool,
+    git_only: bool,
+    dry_run: bool,
 ) -> Result<(), String> {
     let cargo_file_content = std::fs::read_to_string(&manifest_path).map_err(|err| {
         format!(
@@ -29,17 +50,47 @@ pub fn handle_patches(
         )
     })?;
 
-    let maybe_patches =
-        extract_patched_crates_and_adjust_toml(cargo_file_content, |p| locate_workspace_folder(p))?;
+    let workspace_root = manifest_path
+        .parent()
+        .ok_or_else(|| format!("Manifest path {} has no parent directory", manifest_path.display()))?;
+
+    let (mut manifest, mut project_list) =
+        match extract_patched_crates_and_adjust_toml(cargo_file_content.clone(), locate_workspace_folder)? {
+            Some((manifest, project_list)) => (manifest, project_list),
+            None => {
+                let manifest = cargo_file_content.parse::<Document>().map_err(|err| {
+                    format!(
+                        "Unable to parse Cargo.toml: {:?} content: {}",
+                        err, cargo_file_content
+                    )
+                })?;
+                (manifest, Vec::new())
+            }
+        };
+
+    let extra_manifests = extract_external_path_deps_and_adjust_toml(
+        &mut manifest,
+        &manifest_path,
+        workspace_root,
+        &mut project_list,
+        locate_workspace_folder,
+    )?;
+
+    if !project_list.is_empty() {
+        let mut manifests_to_write = vec![PatchedManifest {
+            relative_path: PathBuf::from("Cargo.toml"),
+            document: manifest,
+        }];
+        manifests_to_write.extend(extra_manifests);
 
-    if let Some((patched_cargo_doc, project_list)) = maybe_patches {
         copy_patches_to_remote(
-            &build_path,
-            &build_server,
-            patched_cargo_doc,
+            build_path,
+            build_server,
+            manifests_to_write,
             project_list,
             copy_hidden_files,
-            no_transfer_git,
+            git_only,
+            dry_run,
         )?;
     }
     Ok(())
@@ -187,13 +238,308 @@ fn extract_patched_crates_and_adjust_toml<F: Fn(PathBuf) -> Result<PathBuf, Stri
     Ok(Some((manifest, workspaces_to_copy)))
 }
 
+/// Maps a dependency's `kind` to the Cargo.toml table it's declared in.
+fn dependency_table_name(kind: DependencyKind) -> Option<&'static str> {
+    match kind {
+        DependencyKind::Normal => Some("dependencies"),
+        DependencyKind::Development => Some("dev-dependencies"),
+        DependencyKind::Build => Some("build-dependencies"),
+        _ => None,
+    }
+}
+
+/// The subset of a `cargo_metadata::Dependency` that
+/// `scan_and_adjust_dependencies` needs, kept as our own plain struct (rather
+/// than passing `cargo_metadata::Dependency` straight through) so that logic
+/// stays a pure function of a dependency list and is unit-testable without
+/// shelling out to `cargo metadata`.
+struct PathDependency {
+    key: String,
+    kind: DependencyKind,
+    path: PathBuf,
+}
+
+/// A workspace member's manifest, other than the project's own `Cargo.toml`,
+/// that had a `path` dependency rewritten and needs to be overwritten on the
+/// remote once the workspace tree has been rsynced there.
+struct PatchedManifest {
+    /// Path of the manifest relative to `workspace_root`, e.g. `member/Cargo.toml`.
+    relative_path: PathBuf,
+    document: Document,
+}
+
+/// Rewrites `path` dependencies in `manifest`'s dependency tables that point
+/// outside `workspace_root` -- as opposed to a path dependency on another
+/// member of the same workspace, which doesn't need to move. `depth` is how
+/// many directories `manifest` lives below `workspace_root` (0 for the
+/// workspace root manifest itself), used to prepend the right number of
+/// `../` so a nested member manifest can still reach a relocated workspace.
+/// Each relocated workspace is added to `workspaces_to_copy`, reusing an
+/// entry already queued there (as a patch, or as another external
+/// dependency) instead of copying it twice. Returns whether any dependency
+/// was rewritten.
+fn scan_and_adjust_dependencies<F: Fn(PathBuf) -> Result<PathBuf, String>>(
+    manifest: &mut Document,
+    dependencies: &[PathDependency],
+    workspace_root: &Path,
+    depth: usize,
+    workspaces_to_copy: &mut Vec<PatchProject>,
+    locate_workspace: &F,
+) -> Result<bool, String> {
+    let mut changed = false;
+
+    for dep in dependencies {
+        // A path dependency on another member of our own workspace doesn't need
+        // to be relocated; only crates living outside it do.
+        if dep.path.starts_with(workspace_root) {
+            continue;
+        }
+
+        let Some(table_name) = dependency_table_name(dep.kind) else {
+            continue;
+        };
+
+        let Some(deps_table) = manifest[table_name].as_table_like_mut() else {
+            continue;
+        };
+        let Some(dep_item) = deps_table.get_mut(dep.key.as_str()) else {
+            continue;
+        };
+
+        let known_workspace = workspaces_to_copy
+            .iter()
+            .find(|known_target| dep.path.starts_with(&known_target.local_path))
+            .cloned();
+
+        let patch_target = match known_workspace {
+            Some(target) => target,
+            None => {
+                let workspace_folder_path = locate_workspace(dep.path.clone()).map_err(|err| {
+                    format!(
+                        "Can not determine workspace path for dependency '{}' at {}: {}",
+                        dep.key,
+                        dep.path.display(),
+                        err
+                    )
+                })?;
+                let workspace_folder_name = workspace_folder_path
+                    .file_name()
+                    .ok_or("Unable to get file name from workspace folder.")?
+                    .to_owned();
+
+                let mut remote_folder = PathBuf::from("../");
+                remote_folder.push(workspace_folder_name.clone());
+
+                log::debug!(
+                    "Found external path dependency '{}' at '{}', will copy workspace to '{}'",
+                    dep.key,
+                    workspace_folder_path.display(),
+                    remote_folder.display()
+                );
+
+                let target =
+                    PatchProject::new(workspace_folder_name, workspace_folder_path, remote_folder);
+                workspaces_to_copy.push(target.clone());
+                target
+            }
+        };
+
+        let mut new_path = PathBuf::new();
+        for _ in 0..depth {
+            new_path.push("..");
+        }
+        new_path.push(&patch_target.remote_path);
+        new_path.push(
+            dep.path
+                .strip_prefix(&patch_target.local_path)
+                .map_err(|err| format!("Unable to construct remote folder path: {}", err))?,
+        );
+
+        let new_path = new_path.to_str().ok_or("Unable to modify path in toml.")?;
+        if let Some(dep_table) = dep_item.as_table_like_mut() {
+            dep_table.insert("path", toml_edit::value(new_path));
+            changed = true;
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Finds `path` dependencies anywhere in the workspace's dependency graph
+/// that point outside `workspace_root`, by resolving the full dependency
+/// graph via `cargo_metadata` and scanning every `workspace_members` package
+/// -- not just the package at `manifest_path`, which may be a virtual
+/// workspace manifest (`[workspace]` with no `[package]` of its own) with no
+/// dependencies to its own name. `manifest` is patched in place for the
+/// package that actually lives at `manifest_path`; any other workspace
+/// member whose own Cargo.toml needed a rewrite is returned so its manifest
+/// can also be overwritten on the remote once the workspace has been copied
+/// there.
+fn extract_external_path_deps_and_adjust_toml<F: Fn(PathBuf) -> Result<PathBuf, String>>(
+    manifest: &mut Document,
+    manifest_path: &Path,
+    workspace_root: &Path,
+    workspaces_to_copy: &mut Vec<PatchProject>,
+    locate_workspace: F,
+) -> Result<Vec<PatchedManifest>, String> {
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .manifest_path(manifest_path)
+        .exec()
+        .map_err(|err| {
+            format!(
+                "Unable to call cargo metadata on path {}: {:?}",
+                manifest_path.display(),
+                err
+            )
+        })?;
+
+    let member_ids: std::collections::HashSet<_> = metadata.workspace_members.iter().collect();
+    let mut patched_manifests = Vec::new();
+
+    for package in metadata
+        .packages
+        .iter()
+        .filter(|package| member_ids.contains(&package.id))
+    {
+        let package_manifest_path = package.manifest_path.clone().into_std_path_buf();
+        let path_deps: Vec<PathDependency> = package
+            .dependencies
+            .iter()
+            .filter_map(|dep| {
+                Some(PathDependency {
+                    key: dep.rename.clone().unwrap_or_else(|| dep.name.clone()),
+                    kind: dep.kind,
+                    path: dep.path.clone()?.into_std_path_buf(),
+                })
+            })
+            .collect();
+
+        if package_manifest_path.as_path() == manifest_path {
+            scan_and_adjust_dependencies(
+                manifest,
+                &path_deps,
+                workspace_root,
+                0,
+                workspaces_to_copy,
+                &locate_workspace,
+            )?;
+            continue;
+        }
+
+        let member_content = std::fs::read_to_string(&package_manifest_path).map_err(|err| {
+            format!(
+                "Unable to read cargo manifest at {}: {:?}",
+                package_manifest_path.display(),
+                err
+            )
+        })?;
+        let mut member_manifest = member_content.parse::<Document>().map_err(|err| {
+            format!(
+                "Unable to parse Cargo.toml: {:?} content: {}",
+                err, member_content
+            )
+        })?;
+
+        let member_dir = package_manifest_path.parent().ok_or_else(|| {
+            format!(
+                "Manifest path {} has no parent directory",
+                package_manifest_path.display()
+            )
+        })?;
+        let depth = member_dir
+            .strip_prefix(workspace_root)
+            .map(|rel| rel.components().count())
+            .unwrap_or(0);
+
+        let changed = scan_and_adjust_dependencies(
+            &mut member_manifest,
+            &path_deps,
+            workspace_root,
+            depth,
+            workspaces_to_copy,
+            &locate_workspace,
+        )?;
+
+        if changed {
+            let relative_path = package_manifest_path
+                .strip_prefix(workspace_root)
+                .map_err(|err| format!("Unable to construct relative manifest path: {}", err))?
+                .to_path_buf();
+            patched_manifests.push(PatchedManifest {
+                relative_path,
+                document: member_manifest,
+            });
+        }
+    }
+
+    Ok(patched_manifests)
+}
+
+/// Overwrites `remote_path` on `build_server` with `document`'s contents by
+/// piping it through `ssh ... cat > remote_path`. Used both for the
+/// project's own `Cargo.toml` and for any other workspace member manifest
+/// `extract_external_path_deps_and_adjust_toml` had to rewrite.
+fn write_manifest_to_remote(
+    build_server: &str,
+    remote_path: &str,
+    document: &Document,
+    dry_run: bool,
+) -> Result<(), String> {
+    let mut write_toml = Command::new("ssh");
+    write_toml
+        .arg("-T")
+        .arg(build_server)
+        .arg("cat > ")
+        .arg(remote_path)
+        .stdin(Stdio::piped());
+
+    log::info!(
+        "$ echo <patched Cargo.toml> | {}",
+        crate::format_command(&write_toml)
+    );
+
+    if dry_run {
+        return Ok(());
+    }
+
+    let mut child = write_toml
+        .spawn()
+        .map_err(|err| format!("Unable to copy patched Cargo.toml to remote: {}", err))?;
+
+    let write_result = child
+        .stdin
+        .take()
+        .ok_or_else(|| "Unable to open stdin for remote Cargo.toml transfer".to_string())
+        .and_then(|mut stdin| {
+            stdin
+                .write_all(document.to_string().as_bytes())
+                .map_err(|err| format!("Unable to copy patched Cargo.toml to remote: {}", err))
+        });
+
+    let output = child
+        .wait_with_output()
+        .map_err(|err| format!("Unable to copy patched Cargo.toml to remote: {}", err))?;
+
+    write_result?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "Remote Cargo.toml transfer exited with status {}",
+            output.status
+        ));
+    }
+
+    Ok(())
+}
+
 fn copy_patches_to_remote(
-    build_path: &String,
-    build_server: &String,
-    patched_cargo_doc: Document,
+    build_path: &str,
+    build_server: &str,
+    manifests_to_write: Vec<PatchedManifest>,
     projects_to_copy: Vec<PatchProject>,
     copy_hidden_files: bool,
-    no_transfer_git: bool,
+    git_only: bool,
+    dry_run: bool,
 ) -> Result<(), String> {
     for patch_operation in projects_to_copy.iter() {
         let local_proj_path = format!("{}/", patch_operation.local_path.display());
@@ -209,7 +555,14 @@ fn copy_patches_to_remote(
             &remote_proj_path
         );
         // transfer project to build server
-        copy_to_remote(&local_proj_path, &remote_proj_path, copy_hidden_files, no_transfer_git).map_err(|err| {
+        copy_to_remote(
+            &local_proj_path,
+            &remote_proj_path,
+            copy_hidden_files,
+            git_only,
+            dry_run,
+        )
+        .map_err(|err| {
             format!(
                 "Failed to transfer project {} to build server (error: {})",
                 local_proj_path, err
@@ -217,24 +570,15 @@ fn copy_patches_to_remote(
         })?;
     }
 
-    let remote_toml_path = format!("{}/Cargo.toml", build_path);
-    log::debug!("Writing adjusted Cargo.toml to {}.", &remote_toml_path);
-    let mut child = Command::new("ssh")
-        .args(&[build_server, "-T", "cat > ", &remote_toml_path])
-        .stdin(Stdio::piped())
-        .spawn()
-        .unwrap();
-
-    child
-        .stdin
-        .take()
-        .unwrap()
-        .write_all(patched_cargo_doc.to_string().as_bytes())
-        .map_err(|err| format!("Unable to copy patched Cargo.toml to remote: {}", err))?;
+    for manifest in &manifests_to_write {
+        let remote_path = format!(
+            "{}/{}",
+            build_path.trim_end_matches('/'),
+            manifest.relative_path.display()
+        );
+        write_manifest_to_remote(build_server, &remote_path, &manifest.document, dry_run)?;
+    }
 
-    child
-        .wait_with_output()
-        .map_err(|err| format!("Unable to copy patched Cargo.toml to remote: {}", err))?;
     Ok(())
 }
 
@@ -242,7 +586,12 @@ fn copy_patches_to_remote(
 mod tests {
     use std::path::PathBuf;
 
-    use crate::patches::extract_patched_crates_and_adjust_toml;
+    use cargo_metadata::DependencyKind;
+    use toml_edit::Document;
+
+    use crate::patches::{
+        extract_patched_crates_and_adjust_toml, scan_and_adjust_dependencies, PathDependency,
+    };
 
     #[test]
     fn simple_modification_replaces_path() {
@@ -287,4 +636,113 @@ git-patched-crate = { git = "https://some-url/test/test" }
         .unwrap();
         assert_eq!(result.0.to_string(), expect);
     }
+
+    #[test]
+    fn external_path_dependency_is_relocated_and_workspace_reused() {
+        let input = r#"
+[dependencies]
+in-workspace-crate = { path = "/some/prefix/proj/in-workspace-crate" }
+external-crate = { path = "/some/prefix/a/src/a-crate" }
+
+[dev-dependencies]
+external-crate-again = { path = "/some/prefix/a/src/subfolder/a-other-crate" }
+"#;
+        let expect = r#"
+[dependencies]
+in-workspace-crate = { path = "/some/prefix/proj/in-workspace-crate" }
+external-crate = { path = "../a/src/a-crate" }
+
+[dev-dependencies]
+external-crate-again = { path = "../a/src/subfolder/a-other-crate" }
+"#;
+
+        let mut manifest = input.parse::<Document>().expect("valid toml");
+        let workspace_root = PathBuf::from("/some/prefix/proj");
+        let mut workspaces_to_copy = Vec::new();
+
+        // One dependency inside the workspace (left alone) and two outside it
+        // that share the same external workspace (only copied once).
+        let dependencies = vec![
+            PathDependency {
+                key: "in-workspace-crate".to_string(),
+                kind: DependencyKind::Normal,
+                path: PathBuf::from("/some/prefix/proj/in-workspace-crate"),
+            },
+            PathDependency {
+                key: "external-crate".to_string(),
+                kind: DependencyKind::Normal,
+                path: PathBuf::from("/some/prefix/a/src/a-crate"),
+            },
+            PathDependency {
+                key: "external-crate-again".to_string(),
+                kind: DependencyKind::Development,
+                path: PathBuf::from("/some/prefix/a/src/subfolder/a-other-crate"),
+            },
+        ];
+
+        let changed = scan_and_adjust_dependencies(
+            &mut manifest,
+            &dependencies,
+            &workspace_root,
+            0,
+            &mut workspaces_to_copy,
+            &|p| {
+                if p.starts_with("/some/prefix/a") {
+                    return Ok(PathBuf::from("/some/prefix/a"));
+                }
+                Err("Invalid Path".to_string())
+            },
+        )
+        .expect("dependency scan failed");
+
+        assert!(changed);
+        assert_eq!(manifest.to_string(), expect);
+        assert_eq!(workspaces_to_copy.len(), 1);
+        assert_eq!(workspaces_to_copy[0].name.to_string_lossy(), "a");
+    }
+
+    #[test]
+    fn external_path_dependency_is_relocated_for_nested_member() {
+        let input = r#"
+[dependencies]
+external-crate = { path = "/some/prefix/a/src/a-crate" }
+"#;
+        let expect = r#"
+[dependencies]
+external-crate = { path = "../../../a/src/a-crate" }
+"#;
+
+        let mut manifest = input.parse::<Document>().expect("valid toml");
+        let workspace_root = PathBuf::from("/some/prefix/proj");
+        let mut workspaces_to_copy = Vec::new();
+
+        let dependencies = vec![PathDependency {
+            key: "external-crate".to_string(),
+            kind: DependencyKind::Normal,
+            path: PathBuf::from("/some/prefix/a/src/a-crate"),
+        }];
+
+        // This member's manifest lives two directories below workspace_root
+        // (e.g. `proj/nested/member/Cargo.toml`), so two extra `../` are
+        // prepended to the workspace's own `../a` relocation path.
+        let changed = scan_and_adjust_dependencies(
+            &mut manifest,
+            &dependencies,
+            &workspace_root,
+            2,
+            &mut workspaces_to_copy,
+            &|p| {
+                if p.starts_with("/some/prefix/a") {
+                    return Ok(PathBuf::from("/some/prefix/a"));
+                }
+                Err("Invalid Path".to_string())
+            },
+        )
+        .expect("dependency scan failed");
+
+        assert!(changed);
+        assert_eq!(manifest.to_string(), expect);
+        assert_eq!(workspaces_to_copy.len(), 1);
+        assert_eq!(workspaces_to_copy[0].name.to_string_lossy(), "a");
+    }
 }